@@ -1,18 +1,34 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::Path;
+use std::future::Future;
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use clap::{Arg, App};
-use rumqttc::{ self, AsyncClient, Event, EventLoop, MqttOptions, Packet, SubscribeFilter, Key, TlsConfiguration, Transport, QoS };
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rand::distributions::Alphanumeric;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rumqttc::{ self, AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Packet, Publish, Request, SubAck, SubscribeFilter, SubscribeReasonCode, Key, Outgoing, TlsConfiguration, Transport, QoS };
 use rustls::ClientConfig;
-use serde::{Deserialize, Serialize};
-use tokio::{task};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::{runtime, signal, task, time};
+use tokio::sync::{mpsc, Semaphore};
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum KeyType { RSA, ECC }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -49,22 +65,148 @@ struct ConnectionConfig {
     #[serde(default = "ConnectionConfig::default_port")]
     port: u16,
     #[serde(default = "ConnectionConfig::default_keep_alive")]
-    keep_alive: u64,
+    #[serde(deserialize_with = "deserialize_duration")]
+    keep_alive: Duration,
     #[serde(default = "ConnectionConfig::default_clean_session")]
     clean_session: bool,
     #[serde(default = "ConnectionConfig::default_conn_timeout")]
-    conn_timeout: u64,
+    #[serde(deserialize_with = "deserialize_duration")]
+    conn_timeout: Duration,
     #[serde(default = "ConnectionConfig::default_inflight")]
     inflight: u16,   
+    /// Capacity of the request channel between the client and its event loop. Larger values
+    /// absorb bigger bursts without back-pressure at the cost of memory for queued requests.
+    #[serde(default)]
+    channel_capacity: Option<usize>,
+    #[serde(default)]
+    subscribe_ack_timeout_secs: Option<u64>,
+    #[serde(default)]
+    retry_failed_subscriptions: bool,
+    #[serde(default)]
+    subscribe_batch_size: Option<usize>,
+    #[serde(default)]
+    tls_disable_sni: bool,
+    #[serde(default)]
+    credentials_file: Option<String>,
+    #[serde(default)]
+    unsubscribe_on_disconnect: bool,
+    #[serde(default)]
+    heartbeat_check: Option<HeartbeatCheckConfig>,
+    #[serde(default)]
+    test_publish_on_connect: Option<TestPublishConfig>,
+    #[serde(default)]
+    pubrec_timeout_ms: Option<u64>,
+    #[serde(default)]
+    error_classification: Option<ErrorClassification>,
+    #[serde(default)]
+    cpu_affinity: Option<Vec<usize>>,
+    #[serde(default)]
+    reconnect_notify_topic: Option<String>,
+    #[serde(default)]
+    reconnect_before_reload: Option<u32>,
+    #[serde(default)]
+    max_keep_alive_secs: Option<u16>,
+    #[serde(default)]
+    ping_response_timeout_secs: Option<u64>,
+    #[serde(default)]
+    max_publish_packet_size: Option<u32>,
+    #[serde(default)]
+    overflow_action: OverflowAction,
+    #[serde(rename = "randomizeClientID")]
+    #[serde(default)]
+    randomize_client_id: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+enum OverflowAction {
+    #[default]
+    Drop,
+    Truncate,
+    SendToErrorTopic(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ErrorClassification {
+    patterns: Vec<ErrorPattern>,
+    #[serde(default = "ErrorClassification::default_action")]
+    default_action: ErrorAction,
+}
+
+impl ErrorClassification {
+    fn default_action() -> ErrorAction { ErrorAction::Throttle }
+
+    fn classify(&self, error: &str) -> ErrorAction {
+        self.patterns.iter()
+            .find(|pattern| error.contains(&pattern.message_contains))
+            .map_or(self.default_action, |pattern| pattern.action)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ErrorPattern {
+    message_contains: String,
+    action: ErrorAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum ErrorAction {
+    Transient,
+    Throttle,
+    Fatal,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ReplayOnReconnectConfig {
+    max_messages: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TestPublishConfig {
+    topic: String,
+    payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HeartbeatCheckConfig {
+    expected_topic: String,
+    timeout_secs: u64,
 }
 
 impl ConnectionConfig {
     fn default_client_id() -> String { String::from("rust-mqtt-repeater") }
-    fn default_keep_alive() -> u64 { 30 }
-    fn default_conn_timeout() -> u64 { 5 }
+    fn default_keep_alive() -> Duration { Duration::from_secs(30) }
+    fn default_conn_timeout() -> Duration { Duration::from_secs(5) }
     fn default_inflight() -> u16 { 100 }
     fn default_port() -> u16 { 8883 }
     fn default_clean_session() -> bool { true }
+
+    fn effective_keep_alive(&self) -> Duration {
+        match self.max_keep_alive_secs {
+            Some(max_secs) => self.keep_alive.min(Duration::from_secs(max_secs.into())),
+            None => self.keep_alive,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    Human(String),
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error> where D: Deserializer<'de> {
+    match DurationValue::deserialize(deserializer)? {
+        DurationValue::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        DurationValue::Human(s) => humantime::parse_duration(&s).map_err(de::Error::custom),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -72,7 +214,38 @@ impl ConnectionConfig {
 enum Behaviour {
     Copy,
     Omit,
-    InvertBoolean
+    InvertBoolean,
+    JsonMergeWith(serde_json::Value),
+    CoerceSchema { schema: serde_json::Value },
+    WrapInEnvelope {
+        #[serde(rename = "envelopeFields")]
+        #[serde(default = "Behaviour::default_envelope_fields")]
+        envelope_fields: Vec<EnvelopeField>,
+    },
+}
+
+impl Behaviour {
+    fn default_envelope_fields() -> Vec<EnvelopeField> {
+        vec![
+            EnvelopeField::SourceTopic,
+            EnvelopeField::DestTopic,
+            EnvelopeField::Timestamp,
+            EnvelopeField::MessageId,
+            EnvelopeField::RepeaterVersion,
+            EnvelopeField::HopCount,
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+enum EnvelopeField {
+    SourceTopic,
+    DestTopic,
+    Timestamp,
+    MessageId,
+    RepeaterVersion,
+    HopCount,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -86,14 +259,248 @@ enum Payload {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct Topic {
-    from: String,
+    from: TopicSource,
     to: String,
     #[serde(default = "Topic::default_payload")]
-    payload: Payload
+    payload: Payload,
+    #[serde(default)]
+    dedup_window_ms: Option<u64>,
+    #[serde(default = "Topic::default_qos_policy")]
+    qos_policy: QosPolicy,
+    #[serde(default = "Topic::default_null_policy")]
+    null_policy: JsonNullPolicy,
+    #[serde(default)]
+    max_age_secs: Option<u64>,
+    #[serde(default)]
+    timestamp_pointer: Option<String>,
+    #[serde(default = "Topic::default_unknown_timestamp_action")]
+    unknown_timestamp_action: UnknownTimestampAction,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    verbose: Option<bool>,
+    #[serde(default)]
+    on_transform_error: TransformErrorAction,
+    #[serde(default)]
+    error_topic: Option<String>,
+    #[serde(default)]
+    to_case: Option<CaseConversion>,
+    #[serde(default)]
+    qos_suffix: Option<QosSuffix>,
+    #[serde(default)]
+    last_value_cache: bool,
+    #[serde(default)]
+    cache_ttl_secs: Option<u64>,
+    #[serde(default)]
+    replay_on_reconnect: Option<ReplayOnReconnectConfig>,
 }
 
 impl Topic {
     fn default_payload() -> Payload { Payload::Behaviour(Behaviour::Copy) }
+    fn default_qos_policy() -> QosPolicy { QosPolicy::Force(1) }
+    fn default_null_policy() -> JsonNullPolicy { JsonNullPolicy::Forward }
+    fn default_unknown_timestamp_action() -> UnknownTimestampAction { UnknownTimestampAction::Forward }
+
+    fn timestamp_pointer(&self) -> &str {
+        self.timestamp_pointer.as_deref().unwrap_or("/timestamp")
+    }
+
+    fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl_secs.map(Duration::from_secs)
+    }
+
+    fn dedup_window(&self) -> Option<Duration> {
+        self.dedup_window_ms.filter(|&ms| ms > 0).map(Duration::from_millis)
+    }
+
+    fn iter_from(&self) -> impl Iterator<Item = &str> {
+        self.iter_subscriptions().map(|(path, _)| path)
+    }
+
+    fn iter_subscriptions(&self) -> Box<dyn Iterator<Item = (&str, u8)> + '_> {
+        match &self.from {
+            TopicSource::Single(from) => Box::new(std::iter::once((from.as_str(), TopicSourceEntry::default_qos()))),
+            TopicSource::Many(from) => Box::new(from.iter().map(|path| (path.as_str(), TopicSourceEntry::default_qos()))),
+            TopicSource::Multi(from) => Box::new(from.iter().map(|entry| (entry.path.as_str(), entry.qos))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+enum TopicOrder {
+    #[default]
+    ConfigOrder,
+    MostSpecific,
+    Alphabetical,
+    Priority,
+}
+
+struct CompiledTopic<'a> {
+    filter: &'a str,
+    topic: &'a Topic,
+}
+
+fn compile_topics<'a>(topics: &'a [Topic], order: &TopicOrder) -> Vec<CompiledTopic<'a>> {
+    let mut compiled = topics.iter()
+        .flat_map(|topic| topic.iter_from().map(move |filter| CompiledTopic { filter, topic }))
+        .collect::<Vec<_>>();
+    // All sorts are stable, so entries that compare equal keep their config order
+    match order {
+        TopicOrder::ConfigOrder => {},
+        TopicOrder::MostSpecific => compiled.sort_by_key(|compiled| {
+            if compiled.filter.contains('#') {
+                2
+            } else if compiled.filter.contains('+') {
+                1
+            } else {
+                0
+            }
+        }),
+        TopicOrder::Alphabetical => compiled.sort_by_key(|compiled| compiled.filter),
+        TopicOrder::Priority => compiled.sort_by_key(|compiled| std::cmp::Reverse(compiled.topic.priority)),
+    }
+    compiled
+}
+
+fn matching_topic<'a>(compiled_topics: &[CompiledTopic<'a>], topic: &str) -> Option<&'a Topic> {
+    compiled_topics.iter().find(|compiled| rumqttc::matches(topic, compiled.filter)).map(|compiled| compiled.topic)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+enum TransformErrorAction {
+    #[default]
+    Drop,
+    Forward,
+    ForwardToErrorTopic,
+    Exit,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QosSuffix {
+    #[serde(default)]
+    qos0_suffix: String,
+    #[serde(default)]
+    qos1_suffix: String,
+    #[serde(default)]
+    qos2_suffix: String,
+}
+
+impl QosSuffix {
+    fn for_qos(&self, qos: QoS) -> &str {
+        match qos {
+            QoS::AtMostOnce => &self.qos0_suffix,
+            QoS::AtLeastOnce => &self.qos1_suffix,
+            QoS::ExactlyOnce => &self.qos2_suffix,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum CaseConversion {
+    Lower,
+    Upper,
+    SnakeCase,
+    KebabCase,
+}
+
+impl CaseConversion {
+    fn apply(&self, topic: &str) -> String {
+        match self {
+            CaseConversion::Lower => topic.to_lowercase(),
+            CaseConversion::Upper => topic.to_uppercase(),
+            CaseConversion::SnakeCase => separate_words(topic, '_'),
+            CaseConversion::KebabCase => separate_words(topic, '-'),
+        }
+    }
+}
+
+// Lowercases each topic level, joining words (split on "-", "_", spaces and lower to upper case transitions) with separator
+fn separate_words(topic: &str, separator: char) -> String {
+    let mut converted = String::with_capacity(topic.len());
+    let mut previous: Option<char> = None;
+    for c in topic.chars() {
+        if c == '-' || c == '_' || c == ' ' {
+            converted.push(separator);
+        } else if c.is_uppercase() {
+            if previous.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit()) {
+                converted.push(separator);
+            }
+            converted.extend(c.to_lowercase());
+        } else {
+            converted.push(c);
+        }
+        previous = Some(c);
+    }
+    converted
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum UnknownTimestampAction {
+    Drop,
+    Forward,
+    UseCurrentTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum JsonNullPolicy {
+    Forward,
+    Drop,
+    Remove,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum QosPolicy {
+    Inherit,
+    Upgrade(u8),
+    Downgrade(u8),
+    Force(u8),
+}
+
+impl QosPolicy {
+    fn configured_qos(&self) -> Option<u8> {
+        match self {
+            QosPolicy::Inherit => None,
+            QosPolicy::Upgrade(qos) | QosPolicy::Downgrade(qos) | QosPolicy::Force(qos) => Some(*qos),
+        }
+    }
+
+    fn effective_qos(&self, source_qos: QoS) -> QoS {
+        let source_qos = source_qos as u8;
+        let qos = match self {
+            QosPolicy::Inherit => source_qos,
+            QosPolicy::Upgrade(qos) => source_qos.max(*qos),
+            QosPolicy::Downgrade(qos) => source_qos.min(*qos),
+            QosPolicy::Force(qos) => *qos,
+        };
+        rumqttc::qos(qos).expect("Invalid publish QoS")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum TopicSource {
+    Single(String),
+    Many(Vec<String>),
+    Multi(Vec<TopicSourceEntry>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TopicSourceEntry {
+    path: String,
+    #[serde(default = "TopicSourceEntry::default_qos")]
+    qos: u8,
+}
+
+impl TopicSourceEntry {
+    fn default_qos() -> u8 { 1 }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -102,6 +509,132 @@ struct Config {
     source: ConnectionConfig,
     destination: ConnectionConfig,
     topics: Vec<Topic>,
+    #[serde(default)]
+    startup_sequence_log: bool,
+    #[serde(default)]
+    end_to_end_flow_control: bool,
+    #[serde(default)]
+    reconnect: ReconnectPolicy,
+    #[serde(default = "Config::default_stats_interval_secs")]
+    stats_interval_secs: u64,
+    #[serde(default)]
+    publish_last_seen: Option<String>,
+    #[serde(default)]
+    heartbeat: Option<HeartbeatConfig>,
+    #[serde(default)]
+    max_topics: Option<usize>,
+    #[serde(default)]
+    topic_evaluation_order: TopicOrder,
+    #[serde(default)]
+    disallow_wildcards: bool,
+    #[serde(default)]
+    acl_validation_on_startup: bool,
+    #[serde(default)]
+    verify_destination_subscriptions: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HeartbeatConfig {
+    topic: String,
+    interval_secs: u64,
+    #[serde(default = "HeartbeatConfig::default_payload")]
+    payload: String,
+}
+
+impl HeartbeatConfig {
+    fn default_payload() -> String { String::from("{\"status\":\"running\",\"uptime\":{uptime}}") }
+}
+
+impl Config {
+    fn default_stats_interval_secs() -> u64 { 60 }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ReconnectPolicy {
+    #[serde(default = "ReconnectPolicy::default_initial_backoff_ms")]
+    initial_backoff_ms: u64,
+    #[serde(default = "ReconnectPolicy::default_max_backoff_ms")]
+    max_backoff_ms: u64,
+    #[serde(default = "ReconnectPolicy::default_max_jitter_fraction")]
+    max_jitter_fraction: f64,
+    #[serde(default)]
+    tcp_reconnect_delay_ms: Option<u64>,
+    #[serde(default)]
+    max_dest_reconnect_interval_secs: Option<u64>,
+}
+
+impl ReconnectPolicy {
+    fn default_initial_backoff_ms() -> u64 { 1000 }
+    fn default_max_backoff_ms() -> u64 { 60000 }
+    fn default_max_jitter_fraction() -> f64 { 0.2 }
+
+    fn for_destination(&self) -> ReconnectPolicy {
+        let mut policy = self.clone();
+        if let Some(secs) = self.max_dest_reconnect_interval_secs {
+            policy.max_backoff_ms = secs.saturating_mul(1000);
+        }
+        policy
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff_ms: ReconnectPolicy::default_initial_backoff_ms(),
+            max_backoff_ms: ReconnectPolicy::default_max_backoff_ms(),
+            max_jitter_fraction: ReconnectPolicy::default_max_jitter_fraction(),
+            tcp_reconnect_delay_ms: None,
+            max_dest_reconnect_interval_secs: None,
+        }
+    }
+}
+
+struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+    rng: SmallRng,
+}
+
+impl Backoff {
+    // Jitter is seeded from the client id, so it is reproducible for a given client while
+    // instances with different client ids still spread out their reconnects
+    fn new(policy: ReconnectPolicy, client_id: &str) -> Backoff {
+        Backoff { policy, attempt: 0, rng: SmallRng::seed_from_u64(xxh3_64(client_id.as_bytes())) }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let backoff_ms = self.policy.initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(self.attempt))
+            .min(self.policy.max_backoff_ms);
+        let max_jitter_ms = (backoff_ms as f64 * self.policy.max_jitter_fraction) as u64;
+        let jitter_ms = if max_jitter_ms > 0 { self.rng.gen_range(0..max_jitter_ms) } else { 0 };
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_millis(backoff_ms + jitter_ms).max(self.min_delay())
+    }
+
+    fn min_delay(&self) -> Duration {
+        Duration::from_millis(self.policy.tcp_reconnect_delay_ms.unwrap_or(0))
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StartupLog {
+    enabled: bool,
+    started: Instant,
+}
+
+impl StartupLog {
+    fn step(&self, step: &str) {
+        if self.enabled {
+            println!("[STARTUP +{:.3}s] {}", self.started.elapsed().as_secs_f64(), step);
+        }
+    }
 }
 
 
@@ -124,66 +657,338 @@ async fn main() {
             .get_matches();
 
 
+    let started = Instant::now();
     let config_file_path = Path::new(matches.value_of("config").unwrap_or("config.json"));
     let is_verbose = matches.is_present("verbose");
 
-    let config_string : String;
-    match fs::read_to_string(config_file_path) {
-        Ok(cs) => config_string = cs,
+    let config_string = match fs::read_to_string(config_file_path) {
+        Ok(cs) => cs,
         Err(e) => {
             println!("Unable to read config file from {}: {}", config_file_path.display(), e);
             return;
         }
-    
-    }
+    };
     let config : Config = serde_json::from_str(&config_string).expect("Failed to parse config");
 
+    if let Err(e) = validate_config(&config) {
+        println!("Invalid config: {}", e);
+        return;
+    }
+
+    let startup_log = StartupLog { enabled: config.startup_sequence_log, started };
+    startup_log.step("config loaded");
+
     let (src_client, mut src_eventloop) = make_client(&config.source);
+    startup_log.step("source client created");
     let (dest_client, mut dest_eventloop) = make_client(&config.destination);
+    startup_log.step("destination client created");
+
+    let (_src_credentials_watcher, src_credentials) = watch_credentials_file(&config.source, &src_client, "SRC");
+    let (_dest_credentials_watcher, dest_credentials) = watch_credentials_file(&config.destination, &dest_client, "DEST");
+
+    let dest_inflight = if config.end_to_end_flow_control {
+        Some(Arc::new(Semaphore::new(config.destination.inflight.into())))
+    } else {
+        None
+    };
+    let src_dest_inflight = dest_inflight.clone();
+
+    let dest_reconnect = config.reconnect.for_destination();
+    let dest_error_classification = config.destination.error_classification.clone();
+    let src_cpu_affinity = config.source.cpu_affinity.clone();
+    let dest_cpu_affinity = config.destination.cpu_affinity.clone();
+    let dest_reconnect_notify_topic = config.destination.reconnect_notify_topic.clone();
+    let dest_reconnect_before_reload = config.destination.reconnect_before_reload;
+    let src_ping_response_timeout = config.source.ping_response_timeout_secs.map(Duration::from_secs);
+    let dest_ping_response_timeout = config.destination.ping_response_timeout_secs.map(Duration::from_secs);
+    let src_config_file_path = config_file_path.to_path_buf();
+    let dest_config_file_path = config_file_path.to_path_buf();
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let src_shutting_down = shutting_down.clone();
+    let dest_shutting_down = shutting_down.clone();
+    let src_shutdown_client = src_client.clone();
+    let dest_shutdown_client = dest_client.clone();
+    let dest_loop_client = dest_client.clone();
+    let pubrec_timeout = config.destination.pubrec_timeout_ms.map(Duration::from_millis);
+    let unsubscribe_topics = if config.source.unsubscribe_on_disconnect {
+        config.topics.iter().flat_map(Topic::iter_from).map(String::from).collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    let shutdown_timeout = config.source.conn_timeout.max(config.destination.conn_timeout);
+
+    let last_seen: Arc<Mutex<HashMap<String, u128>>> = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(last_seen_topic) = config.publish_last_seen.clone() {
+        let last_seen = last_seen.clone();
+        let dest_client = dest_client.clone();
+        let dest_inflight = dest_inflight.clone();
+        let mut stats_interval = time::interval(Duration::from_secs(config.stats_interval_secs));
+        task::spawn(async move {
+            stats_interval.tick().await;
+            loop {
+                stats_interval.tick().await;
+                let snapshot = last_seen.lock().unwrap().clone();
+                for (topic, last_seen_unix_ms) in snapshot {
+                    let payload = serde_json::json!({ "topic": topic, "last_seen_unix_ms": last_seen_unix_ms });
+                    acquire_dest_permit(dest_inflight.as_deref()).await;
+                    if let Err(e) = dest_client.publish(&last_seen_topic, QoS::AtLeastOnce, true, payload.to_string()).await {
+                        println!("[DEST ERROR] Failed to publish last seen for {}: {}", topic, e);
+                    }
+                }
+            }
+        });
+    }
+    let track_last_seen = config.publish_last_seen.is_some();
+
+    let last_values: LastValueCache = Arc::new(Mutex::new(HashMap::new()));
+    let src_last_values = last_values.clone();
+    let replay_buffers: ReplayBuffers = Arc::new(Mutex::new(HashMap::new()));
+    let src_replay_buffers = replay_buffers.clone();
+    if let Some(sweep_period) = config.topics.iter().filter(|t| t.last_value_cache).filter_map(Topic::cache_ttl).min() {
+        let last_values = last_values.clone();
+        let mut sweep_interval = time::interval(sweep_period);
+        task::spawn(async move {
+            loop {
+                sweep_interval.tick().await;
+                last_values.lock().unwrap().retain(|_, cached| !cached.is_expired());
+            }
+        });
+    }
+
+    if let Some(heartbeat) = &config.heartbeat {
+        let topic = heartbeat.topic.clone();
+        let payload = heartbeat.payload.clone();
+        let dest_client = dest_client.clone();
+        let dest_inflight = dest_inflight.clone();
+        let mut heartbeat_interval = time::interval(Duration::from_secs(heartbeat.interval_secs));
+        task::spawn(async move {
+            loop {
+                heartbeat_interval.tick().await;
+                let payload = payload.replace("{uptime}", &started.elapsed().as_secs().to_string());
+                acquire_dest_permit(dest_inflight.as_deref()).await;
+                if let Err(e) = dest_client.publish(&topic, QoS::AtMostOnce, false, payload).await {
+                    println!("[WARNING] Failed to publish heartbeat: {}", e);
+                }
+            }
+        });
+    }
 
-    let topics_lookup = config.topics.iter().map(|t| (t.from.clone(), (t.to.clone(), t.payload.clone()))).collect::<HashMap<_, _>>();
+    let unverified: UnverifiedForwards = Arc::new(Mutex::new(HashMap::new()));
+    let src_unverified = if config.verify_destination_subscriptions { Some(unverified.clone()) } else { None };
+    if config.verify_destination_subscriptions {
+        spawn_destination_monitor(&config, &dest_eventloop.options.client_id(), unverified);
+    }
 
-    let t1 = task::spawn(async move {
+    let t1 = spawn_event_loop("SRC", src_cpu_affinity, async move {
+        let mut backoff = Backoff::new(config.reconnect.clone(), &src_eventloop.options.client_id());
+        let compiled_topics = compile_topics(&config.topics, &config.topic_evaluation_order);
+        let mut deduplicator = Deduplicator::new(config.topics.iter().filter_map(Topic::dedup_window).max());
+        let suback_timeout = config.source.subscribe_ack_timeout_secs.map(Duration::from_secs);
+        let mut pending_subacks = PendingSubAcks::default();
+        let mut acl_validation = if config.acl_validation_on_startup { Some(AclValidation::default()) } else { None };
+        let mut suback_deadline: Option<time::Instant> = None;
+        let heartbeat_check = config.source.heartbeat_check.as_ref();
+        let test_publish = config.source.test_publish_on_connect.as_ref();
+        let mut test_publish_due = false;
+        let mut test_publish_deadline: Option<time::Instant> = None;
+        let heartbeat_timeout = heartbeat_check.map(|check| Duration::from_secs(check.timeout_secs));
+        let mut heartbeat_deadline: Option<time::Instant> = None;
+        let mut connected_before = false;
+        let mut failed_attempts: u32 = 0;
+        let mut failures_since_reload: u32 = 0;
+        let mut ping_deadline: Option<time::Instant> = None;
+        let mut is_starting = true;
+        startup_log.step("source connecting (TCP, TLS handshake and MQTT CONNECT)");
         loop {
-            match src_eventloop.poll().await {
+            if let Some((login, password)) = src_credentials.lock().unwrap().take() {
+                src_eventloop.options.set_credentials(login, password);
+            }
+            // poll() is never dropped part way, cancelling it during a partial write would corrupt the MQTT
+            // stream, so deadlines are handled in a separate branch while the same poll keeps running
+            let polled = {
+                let poll = src_eventloop.poll();
+                tokio::pin!(poll);
+                loop {
+                    let deadline = suback_deadline.into_iter().chain(heartbeat_deadline).chain(ping_deadline).chain(test_publish_deadline).min();
+                    tokio::select! {
+                        polled = &mut poll => break polled,
+                        _ = sleep_until(deadline) => {
+                            let now = time::Instant::now();
+                            if suback_deadline.is_some_and(|deadline| deadline <= now) {
+                                println!("[SRC ERROR] Timed out waiting for SubAck, {} subscription request(s) not acknowledged", pending_subacks.len());
+                                suback_deadline = None;
+                                if config.source.retry_failed_subscriptions {
+                                    println!("[SRC] Retrying subscriptions");
+                                    pending_subacks = subscribe_sources(&src_client, &config.topics, &config.source, acl_validation.as_mut());
+                                    suback_deadline = suback_timeout.map(|timeout| now + timeout);
+                                }
+                            }
+                            if heartbeat_deadline.is_some_and(|deadline| deadline <= now) {
+                                println!("[SRC ERROR] No message received on heartbeat topic, subscribing again");
+                                pending_subacks = subscribe_sources(&src_client, &config.topics, &config.source, acl_validation.as_mut());
+                                suback_deadline = suback_timeout.map(|timeout| now + timeout);
+                                heartbeat_deadline = heartbeat_timeout.map(|timeout| now + timeout);
+                            }
+                            if test_publish_deadline.is_some_and(|deadline| deadline <= now) {
+                                if let Some(test_publish) = test_publish {
+                                    println!("[SRC ERROR] Test publish to {} was not received back, the source client may not be authorized to publish", test_publish.topic);
+                                }
+                                test_publish_deadline = None;
+                            }
+                            if ping_deadline.is_some_and(|deadline| deadline <= now) {
+                                println!("[SRC ERROR] No PINGRESP received in time, reconnecting");
+                                ping_deadline = None;
+                                request_reconnect(&src_client, "SRC");
+                            }
+                        }
+                    }
+                }
+            };
+            match polled {
                 Ok(src_notification) => {
                     if is_verbose {
-                        print_event("SRC", &src_notification);
+                        // Publishes on topics with verbose set to false are not printed even with --verbose
+                        let is_quiet_topic = match &src_notification {
+                            Event::Incoming(Packet::Publish(publish)) => matching_topic(&compiled_topics, &publish.topic).is_some_and(|t| t.verbose == Some(false)),
+                            _ => false,
+                        };
+                        if !is_quiet_topic {
+                            print_event("SRC", &src_notification);
+                        }
+                    }
+                    if let Some(ping_response_timeout) = src_ping_response_timeout {
+                        match src_notification {
+                            Event::Outgoing(Outgoing::PingReq) => ping_deadline = Some(time::Instant::now() + ping_response_timeout),
+                            Event::Incoming(Packet::PingResp) => ping_deadline = None,
+                            _ => {},
+                        }
+                    }
+
+                    if let Event::Outgoing(Outgoing::Disconnect) = src_notification {
+                        if src_shutting_down.load(Ordering::SeqCst) {
+                            break;
+                        }
+                    }
+
+                    if let Event::Outgoing(Outgoing::Subscribe(pkid)) = src_notification {
+                        if is_starting {
+                            startup_log.step("source subscriptions sent");
+                        }
+                        pending_subacks.sent(pkid);
+                        if let Some(acl_validation) = &mut acl_validation {
+                            acl_validation.sent(pkid);
+                        }
                     }
                     
                     if let Event::Incoming(packet) = src_notification {
                         if let Packet::ConnAck(connack) = packet {
                             if connack.code == rumqttc::v4::ConnectReturnCode::Success {
-                                src_client.subscribe_many(config.topics
-                                    .iter()
-                                    .map(|t| SubscribeFilter { path: t.from.clone(), qos: QoS::AtLeastOnce })
-                                    .collect::<Vec<_>>()
-                                ).await.expect("Failed to subscribe to source topics");
+                                backoff.reset();
+                                if connected_before {
+                                    if let Some(topic) = &config.source.reconnect_notify_topic {
+                                        notify_reconnect(&dest_client, topic, "src", failed_attempts, src_dest_inflight.clone());
+                                    }
+                                }
+                                connected_before = true;
+                                failed_attempts = 0;
+                                if is_starting {
+                                    startup_log.step("source ConnAck received");
+                                }
+                                if let Some(acl_validation) = &mut acl_validation {
+                                    acl_validation.rejected.clear();
+                                }
+                                pending_subacks = subscribe_sources(&src_client, &config.topics, &config.source, acl_validation.as_mut());
+                                test_publish_due = test_publish.is_some();
+                                suback_deadline = suback_timeout.map(|timeout| time::Instant::now() + timeout);
+                                heartbeat_deadline = heartbeat_timeout.map(|timeout| time::Instant::now() + timeout);
+                            }
+                        } else if let Packet::SubAck(suback) = packet {
+                            if let Some(acl_validation) = &mut acl_validation {
+                                acl_validation.check(&suback);
+                            }
+                            if pending_subacks.acked(suback.pkid) {
+                                suback_deadline = None;
+                                // Published once subscribed to the test topic, so that it is received back
+                                if let (true, Some(test_publish)) = (test_publish_due, test_publish) {
+                                    test_publish_due = false;
+                                    test_publish_deadline = Some(time::Instant::now() + config.source.conn_timeout);
+                                    send_test_publish(&src_client, test_publish);
+                                }
+                            }
+                            if is_starting {
+                                startup_log.step("source SubAck received, startup complete");
+                                is_starting = false;
                             }
                         } else if let Packet::Publish(publish) = packet {
-                            if let Some(t) = topics_lookup.get(&publish.topic) {
-                                if is_verbose {
+                            if let Some(test_publish) = test_publish {
+                                if publish.topic == test_publish.topic {
+                                    if test_publish_deadline.is_some() && publish.payload == test_publish.payload.as_bytes() {
+                                        println!("[SRC] Test publish to {} received back, the source client can publish", test_publish.topic);
+                                        test_publish_deadline = None;
+                                    }
+                                    continue;
+                                }
+                            }
+                            if let Some(check) = heartbeat_check {
+                                if rumqttc::matches(&publish.topic, &check.expected_topic) {
+                                    heartbeat_deadline = heartbeat_timeout.map(|timeout| time::Instant::now() + timeout);
+                                }
+                            }
+                            if let Some(t) = matching_topic(&compiled_topics, &publish.topic) {
+                                let is_topic_verbose = t.verbose.unwrap_or(is_verbose);
+                                if let Some(window) = t.dedup_window() {
+                                    if deduplicator.is_duplicate(&publish.topic, &publish.payload, window) {
+                                        if is_topic_verbose {
+                                            println!("[SRC DEDUP] Dropping duplicate payload on {}", publish.topic);
+                                        }
+                                        continue;
+                                    }
+                                }
+                                if is_topic_verbose {
                                     println!("[SRC->DEST] {:?}", t);
                                 }
-                                let to = &t.0;
-                                let payload_behaviour = &t.1;
-                                let new_payload = match payload_behaviour {
-                                    Payload::Behaviour(Behaviour::Copy) => publish.payload,
-                                    Payload::Behaviour(Behaviour::Omit) => String::from("").into(),
-                                    Payload::Behaviour(Behaviour::InvertBoolean) => {
-                                        let payload_string = match String::from_utf8_lossy(&publish.payload).to_lowercase().as_str() {
-                                            "false" | "0" => String::from("true"),
-                                            "true" | "1" => String::from("false"),
-                                            _ => String::from(""),
-                                        };
-                                        payload_string.into()
+                                if track_last_seen {
+                                    let now_unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                                    last_seen.lock().unwrap().insert(publish.topic.clone(), now_unix_ms);
+                                }
+                                let forward = match forward_message(t, &publish, is_topic_verbose) {
+                                    Some(forward) => forward,
+                                    None => continue,
+                                };
+                                let forward = match config.destination.max_publish_packet_size {
+                                    Some(max_size) => match enforce_packet_size(forward, max_size, &config.destination.overflow_action) {
+                                        Some(forward) => forward,
+                                        None => continue,
                                     },
-                                    Payload::String(payload_string) => payload_string.clone().into(),
-                                    Payload::Bytes(bytes) => bytes.to_owned().into(),
+                                    None => forward,
                                 };
 
+                                // Error topics only get the messages a topic failed to handle, they are not re-published
+                                if t.last_value_cache && !forward.rerouted {
+                                    src_last_values.lock().unwrap().insert(forward.to.clone(), CachedValue {
+                                        qos: forward.qos,
+                                        retain: forward.retain,
+                                        payload: forward.payload.clone(),
+                                        received: Instant::now(),
+                                        ttl: t.cache_ttl(),
+                                    });
+                                }
+                                if let Some(replay) = t.replay_on_reconnect.as_ref().filter(|_| !forward.rerouted) {
+                                    let mut replay_buffers = src_replay_buffers.lock().unwrap();
+                                    let buffer = replay_buffers.entry(forward.to.clone()).or_default();
+                                    if buffer.len() == replay.max_messages {
+                                        buffer.pop_front();
+                                    }
+                                    buffer.push_back((forward.payload.clone(), forward.qos, forward.retain));
+                                }
+                                acquire_dest_permit(src_dest_inflight.as_deref()).await;
+                                // Error topics are not among the monitored destination topics
+                                if let Some(unverified) = src_unverified.as_ref().filter(|_| !forward.rerouted) {
+                                    unverified.lock().unwrap().entry(forward.to.clone()).or_insert_with(Instant::now);
+                                }
                                 dest_client
-                                    .publish_bytes(to, QoS::AtLeastOnce, publish.retain, new_payload)
+                                    .publish_bytes(forward.to, forward.qos, forward.retain, forward.payload)
                                     .await
                                     .expect("Failed to publish to destination");
                             }
@@ -191,23 +996,136 @@ async fn main() {
                     }
                 },
                 Err(connection_error) => {
-                    println!("[SRC CONNECTION_ERROR] {}", connection_error.to_string());
+                    ping_deadline = None;
+                    test_publish_due = false;
+                    test_publish_deadline = None;
+                    suback_deadline = None;
+                    heartbeat_deadline = None;
+                    // Cancelled by request_reconnect, reconnect right away without counting it as a failure
+                    if let ConnectionError::Cancel = connection_error {
+                        continue;
+                    }
+                    failed_attempts = failed_attempts.saturating_add(1);
+                    if let Some(reconnect_before_reload) = config.source.reconnect_before_reload {
+                        failures_since_reload += 1;
+                        if failures_since_reload >= reconnect_before_reload {
+                            reload_connection_options(&src_config_file_path, "SRC", |config| &config.source, &mut src_eventloop);
+                            failures_since_reload = 0;
+                        }
+                    }
+                    handle_connection_error("SRC", &connection_error, config.source.error_classification.as_ref(), &mut backoff).await;
                 }
             }
             task::yield_now().await;
         }
     });
 
-    let t2 = task::spawn(async move {
+    let t2 = spawn_event_loop("DEST", dest_cpu_affinity, async move {
+        let mut backoff = Backoff::new(dest_reconnect, &dest_eventloop.options.client_id());
+        let mut awaiting_pubrec: HashMap<u16, time::Instant> = HashMap::new();
+        let mut connected_before = false;
+        let mut failed_attempts: u32 = 0;
+        let mut failures_since_reload: u32 = 0;
+        let mut ping_deadline: Option<time::Instant> = None;
+        let mut is_starting = true;
+        startup_log.step("destination connecting (TCP, TLS handshake and MQTT CONNECT)");
         loop {
-            match dest_eventloop.poll().await {
+            if let Some((login, password)) = dest_credentials.lock().unwrap().take() {
+                dest_eventloop.options.set_credentials(login, password);
+            }
+            // Deadlines are handled alongside the same poll() instead of cancelling it, see the source loop
+            let polled = {
+                let poll = dest_eventloop.poll();
+                tokio::pin!(poll);
+                loop {
+                    let deadline = awaiting_pubrec.values().copied().chain(ping_deadline).min();
+                    tokio::select! {
+                        polled = &mut poll => break polled,
+                        _ = sleep_until(deadline) => {
+                            let now = time::Instant::now();
+                            // A second cancel would stay queued and abort the reconnect, so at most one is sent
+                            if awaiting_pubrec.values().any(|deadline| *deadline <= now) {
+                                println!("[DEST ERROR] {} QoS 2 publish(es) not acknowledged with PUBREC in time, reconnecting to retransmit", awaiting_pubrec.len());
+                                awaiting_pubrec.clear();
+                                ping_deadline = None;
+                                request_reconnect(&dest_loop_client, "DEST");
+                            } else if ping_deadline.is_some_and(|deadline| deadline <= now) {
+                                println!("[DEST ERROR] No PINGRESP received in time, reconnecting");
+                                ping_deadline = None;
+                                request_reconnect(&dest_loop_client, "DEST");
+                            }
+                        }
+                    }
+                }
+            };
+            match polled {
                  Ok(dest_notification) => {
                     if is_verbose {
                         print_event("DEST", &dest_notification);
                     }
+                    if let Some(ping_response_timeout) = dest_ping_response_timeout {
+                        match dest_notification {
+                            Event::Outgoing(Outgoing::PingReq) => ping_deadline = Some(time::Instant::now() + ping_response_timeout),
+                            Event::Incoming(Packet::PingResp) => ping_deadline = None,
+                            _ => {},
+                        }
+                    }
+                    if let Event::Outgoing(Outgoing::Disconnect) = dest_notification {
+                        if dest_shutting_down.load(Ordering::SeqCst) {
+                            break;
+                        }
+                    }
+                    if let Event::Incoming(Packet::ConnAck(_)) = dest_notification {
+                        backoff.reset();
+                        if connected_before {
+                            if let Some(topic) = &dest_reconnect_notify_topic {
+                                notify_reconnect(&dest_loop_client, topic, "dest", failed_attempts, dest_inflight.clone());
+                            }
+                        }
+                        connected_before = true;
+                        failed_attempts = 0;
+                        if is_starting {
+                            startup_log.step("destination ConnAck received");
+                            is_starting = false;
+                        } else {
+                            republish_last_values(&dest_loop_client, &last_values, dest_inflight.clone());
+                            replay_messages(&dest_loop_client, &replay_buffers, dest_inflight.clone());
+                        }
+                    }
+                    if let Some(dest_inflight) = &dest_inflight {
+                        if let Event::Outgoing(Outgoing::Publish(0)) | Event::Incoming(Packet::PubAck(_)) | Event::Incoming(Packet::PubComp(_)) = dest_notification {
+                            dest_inflight.add_permits(1);
+                        }
+                    }
+                    if let Some(pubrec_timeout) = pubrec_timeout {
+                        match dest_notification {
+                            Event::Outgoing(Outgoing::Publish(pkid)) if pkid != 0 && outgoing_qos(&dest_eventloop, pkid) == Some(QoS::ExactlyOnce) => {
+                                awaiting_pubrec.insert(pkid, time::Instant::now() + pubrec_timeout);
+                            },
+                            Event::Incoming(Packet::PubRec(rec)) => { awaiting_pubrec.remove(&rec.pkid); },
+                            _ => {},
+                        }
+                    }
                 },
                 Err(connection_error) => {
-                    println!("[DEST CONNECTION_ERROR] {}", connection_error.to_string());
+                    ping_deadline = None;
+                    if pubrec_timeout.is_some() {
+                        awaiting_pubrec.clear();
+                        mark_pending_as_duplicates(&mut dest_eventloop);
+                    }
+                    // Cancelled by request_reconnect, reconnect right away without counting it as a failure
+                    if let ConnectionError::Cancel = connection_error {
+                        continue;
+                    }
+                    failed_attempts = failed_attempts.saturating_add(1);
+                    if let Some(reconnect_before_reload) = dest_reconnect_before_reload {
+                        failures_since_reload += 1;
+                        if failures_since_reload >= reconnect_before_reload {
+                            reload_connection_options(&dest_config_file_path, "DEST", |config| &config.destination, &mut dest_eventloop);
+                            failures_since_reload = 0;
+                        }
+                    }
+                    handle_connection_error("DEST", &connection_error, dest_error_classification.as_ref(), &mut backoff).await;
                 }
             }
                             
@@ -215,58 +1133,1311 @@ async fn main() {
         }
     });
 
-    let (_first, _second) = tokio::join!(t1, t2);
+    shutdown_signal().await;
+    println!("Shutting down");
+    shutting_down.store(true, Ordering::SeqCst);
+    // Requests are not read while a broker is unreachable, so sending them is bounded by the timeout as well
+    let shutdown = async {
+        let src_shutdown = async {
+            for topic in unsubscribe_topics {
+                if let Err(e) = src_shutdown_client.unsubscribe(topic).await {
+                    println!("[SRC ERROR] Failed to unsubscribe: {}", e);
+                }
+            }
+            let _ = src_shutdown_client.disconnect().await;
+        };
+        let _ = tokio::join!(src_shutdown, dest_shutdown_client.disconnect());
+        tokio::join!(t1, t2)
+    };
+    if time::timeout(shutdown_timeout, shutdown).await.is_err() {
+        println!("Timed out waiting for clients to disconnect");
+        // Event loops pinned with cpuAffinity run as blocking tasks, which dropping the runtime would wait for
+        process::exit(1);
+    }
 }
 
-fn make_client(connection_cfg: &ConnectionConfig) -> (AsyncClient, EventLoop) {
-    let mut mqttoptions = MqttOptions::new(&connection_cfg.client_id, &connection_cfg.host, connection_cfg.port);
-    mqttoptions.set_keep_alive(Duration::new(connection_cfg.keep_alive, 0));
-    mqttoptions.set_inflight(connection_cfg.inflight);
-    mqttoptions.set_clean_session(connection_cfg.clean_session);
-    
+type UnverifiedForwards = Arc<Mutex<HashMap<String, Instant>>>;
 
-    if let Auth::AuthPassword { login, password } = &connection_cfg.auth {
-        let mut roots = rustls::RootCertStore::empty();
-        for cert in rustls_native_certs::load_native_certs().expect("Failed to load platform certs") {
-            roots
-                .add(&rustls::Certificate(cert.0))
-                .expect("Failed to add platform cert");
+// Filter matching every topic a topic entry can publish to, placeholders and QoS suffixes included
+fn monitor_filters(topic: &Topic) -> Vec<String> {
+    let levels = topic.to.split('/')
+        .map(|level| if level.contains('{') { String::from("+") } else { level.to_string() })
+        .collect::<Vec<_>>();
+    let filter = match &topic.to_case {
+        Some(case) => case.apply(&levels.join("/")),
+        None => levels.join("/"),
+    };
+    let qos_suffix = match &topic.qos_suffix {
+        Some(qos_suffix) => qos_suffix,
+        None => return vec![filter],
+    };
+    let mut filters = Vec::new();
+    for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+        let suffix = qos_suffix.for_qos(qos);
+        // A wildcard can only be a whole level, a suffix extending it is already matched by it
+        let suffixed = if filter.ends_with('+') && !suffix.starts_with('/') {
+            filter.clone()
+        } else {
+            format!("{}{}", filter, suffix)
+        };
+        if !filters.contains(&suffixed) {
+            filters.push(suffixed);
+        }
+    }
+    filters
+}
+
+fn spawn_destination_monitor(config: &Config, dest_client_id: &str, unverified: UnverifiedForwards) {
+    let mqttoptions = make_mqtt_options(&config.destination, format!("{}-monitor", dest_client_id))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let (monitor_client, mut monitor_eventloop) = AsyncClient::new(mqttoptions, 10);
+    let filters = config.topics.iter()
+        .flat_map(monitor_filters)
+        .map(|path| SubscribeFilter { path, qos: QoS::AtMostOnce })
+        .collect::<Vec<_>>();
+    let verify_timeout = config.destination.conn_timeout;
+    let mut backoff = Backoff::new(config.reconnect.for_destination(), &monitor_eventloop.options.client_id());
+
+    let sweep_unverified = unverified.clone();
+    let mut sweep_interval = time::interval(verify_timeout);
+    task::spawn(async move {
+        loop {
+            sweep_interval.tick().await;
+            sweep_unverified.lock().unwrap().retain(|topic, forwarded| {
+                if forwarded.elapsed() > verify_timeout {
+                    println!("[WARNING] Message forwarded to {} was not received back from the destination broker", topic);
+                    return false;
+                }
+                true
+            });
+        }
+    });
+
+    task::spawn(async move {
+        loop {
+            match monitor_eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff.reset();
+                    let monitor_client = monitor_client.clone();
+                    let filters = filters.clone();
+                    task::spawn(async move {
+                        if let Err(e) = monitor_client.subscribe_many(filters).await {
+                            println!("[DEST MONITOR ERROR] Failed to subscribe: {}", e);
+                        }
+                    });
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    unverified.lock().unwrap().remove(&publish.topic);
+                },
+                Ok(_) => {},
+                Err(connection_error) => handle_connection_error("DEST MONITOR", &connection_error, None, &mut backoff).await,
+            }
+        }
+    });
+}
+
+fn spawn_event_loop<F>(prefix: &'static str, cpu_affinity: Option<Vec<usize>>, event_loop: F) -> task::JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let cpu_affinity = match cpu_affinity {
+        Some(cpu_affinity) => cpu_affinity,
+        None => return task::spawn(event_loop),
+    };
+
+    // Tasks can move between runtime worker threads, so pinning only works for an event loop
+    // running on its own thread
+    task::spawn_blocking(move || {
+        let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+        match core_ids.into_iter().find(|core_id| cpu_affinity.contains(&core_id.id)) {
+            Some(core_id) if core_affinity::set_for_current(core_id) => println!("[{}] Event loop pinned to CPU {}", prefix, core_id.id),
+            _ => println!("[WARNING] Unable to pin {} event loop to any of CPUs {:?}", prefix, cpu_affinity),
+        }
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to create event loop runtime")
+            .block_on(event_loop)
+    })
+}
+
+async fn handle_connection_error(prefix: &str, connection_error: &ConnectionError, classification: Option<&ErrorClassification>, backoff: &mut Backoff) {
+    println!("[{} CONNECTION_ERROR] {}", prefix, connection_error);
+    let action = classification.map_or(ErrorAction::Throttle, |classification| classification.classify(&connection_error.to_string()));
+    match action {
+        // Not backed off, but still kept apart by tcpReconnectDelayMs
+        ErrorAction::Transient => time::sleep(backoff.min_delay()).await,
+        ErrorAction::Throttle => time::sleep(backoff.next_delay()).await,
+        ErrorAction::Fatal => {
+            println!("[{} FATAL] Exiting due to connection error", prefix);
+            process::exit(1);
+        },
+    }
+}
+
+async fn sleep_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// The destination loop releases a permit for every QoS 0 publish sent and every QoS 1/2 ack, so each publisher
+// on the destination client has to take one first
+async fn acquire_dest_permit(dest_inflight: Option<&Semaphore>) {
+    if let Some(dest_inflight) = dest_inflight {
+        dest_inflight.acquire().await.expect("Destination inflight semaphore closed").forget();
+    }
+}
+
+fn request_reconnect(client: &AsyncClient, prefix: &'static str) {
+    // Cancelling drops the connection right away, the event loop reconnects on its next poll. Sent from
+    // a separate task, as the caller is the event loop that has to keep polling to receive it
+    let client = client.clone();
+    task::spawn(async move {
+        if let Err(e) = client.cancel().await {
+            println!("[{} ERROR] Failed to request reconnect: {}", prefix, e);
+        }
+    });
+}
+
+// MqttState keeps unacknowledged publishes private, a cleaned copy lists them without touching the connection
+fn outgoing_qos(eventloop: &EventLoop, pkid: u16) -> Option<QoS> {
+    eventloop.state.clone().clean().into_iter().find_map(|request| match request {
+        Request::Publish(publish) if publish.pkid == pkid => Some(publish.qos),
+        _ => None,
+    })
+}
+
+fn mark_pending_as_duplicates(eventloop: &mut EventLoop) {
+    let pending = std::mem::take(&mut eventloop.pending).map(|request| match request {
+        Request::Publish(mut publish) => {
+            publish.dup = true;
+            Request::Publish(publish)
+        },
+        request => request,
+    });
+    eventloop.pending = pending.collect::<Vec<_>>().into_iter();
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+}
+
+fn read_credentials_file(path: &Path) -> Result<(String, String), String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let first_line = content.lines().next().unwrap_or_default();
+    first_line.split_once(':')
+        .map(|(login, password)| (login.to_string(), password.to_string()))
+        .ok_or_else(|| String::from("expected \"login:password\" on the first line"))
+}
+
+type PendingCredentials = Arc<Mutex<Option<(String, String)>>>;
+
+fn watch_credentials_file(connection_cfg: &ConnectionConfig, client: &AsyncClient, prefix: &'static str) -> (Option<RecommendedWatcher>, PendingCredentials) {
+    let pending_credentials: PendingCredentials = Arc::new(Mutex::new(None));
+    let path = match &connection_cfg.credentials_file {
+        Some(path) => PathBuf::from(path),
+        None => return (None, pending_credentials),
+    };
+
+    // Watching the parent directory, rather than the file, catches secret agents replacing the
+    // file by renaming a new one into place
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event.paths.iter().any(|changed| changed.file_name().map(|name| name.to_os_string()) == file_name) {
+                let _ = changed_tx.send(());
+            }
+        }
+    }).expect("Failed to create credentials file watcher");
+    let watched_dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    watcher.watch(watched_dir, RecursiveMode::NonRecursive).expect("Failed to watch credentials file");
+
+    let mut current_credentials = read_credentials_file(&path).ok();
+    let client = client.clone();
+    let task_pending_credentials = pending_credentials.clone();
+    task::spawn(async move {
+        while changed_rx.recv().await.is_some() {
+            let credentials = match read_credentials_file(&path) {
+                Ok(credentials) => credentials,
+                Err(e) => {
+                    println!("[{} CREDENTIALS] Unable to read {}: {}", prefix, path.display(), e);
+                    continue;
+                }
+            };
+            if current_credentials.as_ref() == Some(&credentials) {
+                continue;
+            }
+            println!("[{} CREDENTIALS] {} changed, reconnecting with new credentials", prefix, path.display());
+            current_credentials = Some(credentials.clone());
+            *task_pending_credentials.lock().unwrap() = Some(credentials);
+            request_reconnect(&client, prefix);
+        }
+    });
+
+    (Some(watcher), pending_credentials)
+}
+
+fn send_test_publish(client: &AsyncClient, test_publish: &TestPublishConfig) {
+    let client = client.clone();
+    let topic = test_publish.topic.clone();
+    let payload = test_publish.payload.clone();
+    task::spawn(async move {
+        if let Err(e) = client.publish(topic, QoS::AtMostOnce, false, payload).await {
+            println!("[SRC ERROR] Failed to send test publish: {}", e);
+        }
+    });
+}
+
+#[derive(Default)]
+struct AclValidation {
+    unsent: VecDeque<String>,
+    awaiting_suback: HashMap<u16, String>,
+    rejected: HashSet<String>,
+}
+
+impl AclValidation {
+    fn sent(&mut self, pkid: u16) {
+        if let Some(path) = self.unsent.pop_front() {
+            self.awaiting_suback.insert(pkid, path);
+        }
+    }
+
+    fn check(&mut self, suback: &SubAck) {
+        let path = match self.awaiting_suback.remove(&suback.pkid) {
+            Some(path) => path,
+            None => return,
+        };
+        if suback.return_codes.contains(&SubscribeReasonCode::Failure) {
+            println!("[SRC ERROR] Broker rejected subscription to \"{}\", check the broker ACL. Skipping it until reconnect", path);
+            self.rejected.insert(path);
+        }
+    }
+}
+
+// SubAcks for an earlier attempt can still arrive after subscriptions were sent again, so only packet ids
+// sent since the latest attempt are counted
+#[derive(Default)]
+struct PendingSubAcks {
+    unsent: usize,
+    awaiting: HashSet<u16>,
+}
+
+impl PendingSubAcks {
+    fn sent(&mut self, pkid: u16) {
+        if self.unsent > 0 {
+            self.unsent -= 1;
+            self.awaiting.insert(pkid);
+        }
+    }
+
+    // True once the last SubAck of the latest attempt arrived
+    fn acked(&mut self, pkid: u16) -> bool {
+        self.awaiting.remove(&pkid) && self.len() == 0
+    }
+
+    fn len(&self) -> usize {
+        self.unsent + self.awaiting.len()
+    }
+}
+
+fn subscribe_sources(client: &AsyncClient, topics: &[Topic], connection_cfg: &ConnectionConfig, acl_validation: Option<&mut AclValidation>) -> PendingSubAcks {
+    let mut filters = topics
+        .iter()
+        .flat_map(Topic::iter_subscriptions)
+        .map(|(path, qos)| SubscribeFilter { path: path.to_string(), qos: rumqttc::qos(qos).expect("Invalid subscription QoS") })
+        .collect::<Vec<_>>();
+    if let Some(check) = &connection_cfg.heartbeat_check {
+        filters.push(SubscribeFilter { path: check.expected_topic.clone(), qos: QoS::AtMostOnce });
+    }
+    if let Some(test_publish) = &connection_cfg.test_publish_on_connect {
+        filters.push(SubscribeFilter { path: test_publish.topic.clone(), qos: QoS::AtMostOnce });
+    }
+    let mut batch_size = connection_cfg.subscribe_batch_size;
+    // Each topic is subscribed in its own SUBSCRIBE packet, so that SubAck return codes tell which topics were rejected
+    if let Some(acl_validation) = acl_validation {
+        filters.retain(|filter| !acl_validation.rejected.contains(&filter.path));
+        acl_validation.unsent = filters.iter().map(|filter| filter.path.clone()).collect();
+        acl_validation.awaiting_suback.clear();
+        batch_size = Some(1);
+    }
+    let batches = match batch_size {
+        Some(batch_size) => filters.chunks(batch_size).map(<[_]>::to_vec).collect::<Vec<_>>(),
+        None => vec![filters],
+    };
+    let batch_count = batches.len();
+    let log_batches = connection_cfg.subscribe_batch_size.is_some();
+
+    // Requests are sent from a separate task, as the caller is the one polling the event loop and
+    // more batches than the request channel capacity would never be sent otherwise
+    let client = client.clone();
+    task::spawn(async move {
+        for (index, batch) in batches.into_iter().enumerate() {
+            if log_batches {
+                println!("[SRC] Subscribing batch {}/{} with {} topic(s)", index + 1, batch_count, batch.len());
+            }
+            if let Err(e) = client.subscribe_many(batch).await {
+                println!("[SRC ERROR] Failed to subscribe to source topics: {}", e);
+                request_reconnect(&client, "SRC");
+                return;
+            }
+        }
+    });
+    PendingSubAcks { unsent: batch_count, awaiting: HashSet::new() }
+}
+
+struct Deduplicator {
+    last_forwarded: HashMap<String, (Bytes, Instant)>,
+    max_window: Option<Duration>,
+    last_sweep: Instant,
+}
+
+impl Deduplicator {
+    fn new(max_window: Option<Duration>) -> Deduplicator {
+        Deduplicator { last_forwarded: HashMap::new(), max_window, last_sweep: Instant::now() }
+    }
+
+    fn is_duplicate(&mut self, topic: &str, payload: &Bytes, window: Duration) -> bool {
+        let now = Instant::now();
+        self.evict_stale(now);
+
+        if let Some((last_payload, last_forwarded_at)) = self.last_forwarded.get(topic) {
+            if last_payload == payload && now.duration_since(*last_forwarded_at) < window {
+                return true;
+            }
+        }
+        self.last_forwarded.insert(topic.to_string(), (payload.clone(), now));
+        false
+    }
+
+    fn evict_stale(&mut self, now: Instant) {
+        if let Some(max_window) = self.max_window {
+            if now.duration_since(self.last_sweep) >= max_window {
+                self.last_forwarded.retain(|_, (_, last_forwarded_at)| now.duration_since(*last_forwarded_at) < max_window);
+                self.last_sweep = now;
+            }
+        }
+    }
+}
+
+fn validate_config(config: &Config) -> Result<(), String> {
+    for (name, connection_cfg) in [("source", &config.source), ("destination", &config.destination)] {
+        if connection_cfg.ping_response_timeout_secs == Some(0) {
+            return Err(format!("{}.pingResponseTimeoutSecs must be greater than 0", name));
+        }
+        if connection_cfg.max_publish_packet_size == Some(0) {
+            return Err(format!("{}.maxPublishPacketSize must be greater than 0", name));
+        }
+        if connection_cfg.reconnect_before_reload == Some(0) {
+            return Err(format!("{}.reconnectBeforeReload must be greater than 0", name));
+        }
+        if connection_cfg.conn_timeout.as_secs() == 0 {
+            return Err(format!("{}.connTimeout must be at least 1 second", name));
+        }
+        // Both are sent to rumqttc as whole seconds
+        if connection_cfg.conn_timeout.subsec_nanos() != 0 {
+            return Err(format!("{}.connTimeout must be a whole number of seconds", name));
+        }
+        if connection_cfg.keep_alive.subsec_nanos() != 0 {
+            return Err(format!("{}.keepAlive must be a whole number of seconds", name));
+        }
+        if connection_cfg.max_keep_alive_secs.is_some_and(|max_secs| max_secs < 5) {
+            return Err(format!("{}.maxKeepAliveSecs must be at least 5 seconds", name));
+        }
+        if connection_cfg.keep_alive.as_secs() < 5 {
+            return Err(format!("{}.keepAlive must be at least 5 seconds", name));
+        }
+        if connection_cfg.subscribe_ack_timeout_secs == Some(0) {
+            return Err(format!("{}.subscribeAckTimeoutSecs must be greater than 0", name));
+        }
+        if connection_cfg.heartbeat_check.as_ref().is_some_and(|check| check.timeout_secs == 0) {
+            return Err(format!("{}.heartbeatCheck.timeoutSecs must be greater than 0", name));
+        }
+        if connection_cfg.pubrec_timeout_ms == Some(0) {
+            return Err(format!("{}.pubrecTimeoutMs must be greater than 0", name));
+        }
+        if connection_cfg.subscribe_batch_size == Some(0) {
+            return Err(format!("{}.subscribeBatchSize must be greater than 0", name));
+        }
+        if connection_cfg.channel_capacity == Some(0) {
+            return Err(format!("{}.channelCapacity must be greater than 0", name));
+        }
+    }
+
+    if let Some(max_topics) = config.max_topics {
+        if config.topics.len() > max_topics {
+            return Err(format!("{} topics configured, but maxTopics allows at most {}", config.topics.len(), max_topics));
+        }
+    }
+
+    if config.stats_interval_secs == 0 {
+        return Err(String::from("statsIntervalSecs must be greater than 0"));
+    }
+
+    if let Some(heartbeat) = &config.heartbeat {
+        if heartbeat.interval_secs == 0 {
+            return Err(String::from("heartbeat.intervalSecs must be greater than 0"));
+        }
+    }
+
+    let reconnect = &config.reconnect;
+    if !(0.0..=1.0).contains(&reconnect.max_jitter_fraction) {
+        return Err(String::from("reconnect.maxJitterFraction must be between 0.0 and 1.0"));
+    }
+    if reconnect.max_backoff_ms < reconnect.initial_backoff_ms {
+        return Err(String::from("reconnect.maxBackoffMs must not be less than reconnect.initialBackoffMs"));
+    }
+    if reconnect.for_destination().max_backoff_ms < reconnect.initial_backoff_ms {
+        return Err(String::from("reconnect.maxDestReconnectIntervalSecs must not be less than reconnect.initialBackoffMs"));
+    }
+
+    for topic in &config.topics {
+        if topic.replay_on_reconnect.as_ref().is_some_and(|replay| replay.max_messages == 0) {
+            return Err(format!("replayOnReconnect.maxMessages for topic \"{}\" must be greater than 0", topic.to));
+        }
+        if matches!(topic.on_transform_error, TransformErrorAction::ForwardToErrorTopic) && topic.error_topic.is_none() {
+            return Err(format!("onTransformError \"forwardToErrorTopic\" for topic \"{}\" requires errorTopic", topic.to));
+        }
+        if let Some(cache_ttl_secs) = topic.cache_ttl_secs {
+            if cache_ttl_secs == 0 {
+                return Err(format!("cacheTtlSecs for topic \"{}\" must be greater than 0", topic.to));
+            }
+            if !topic.last_value_cache {
+                return Err(format!("cacheTtlSecs for topic \"{}\" requires lastValueCache", topic.to));
+            }
+        }
+        if !topic.timestamp_pointer().starts_with('/') {
+            return Err(format!("timestampPointer for topic \"{}\" must be a JSON pointer starting with \"/\"", topic.to));
+        }
+        if let Some(qos) = topic.qos_policy.configured_qos() {
+            if rumqttc::qos(qos).is_err() {
+                return Err(format!("qosPolicy for topic \"{}\" must use QoS 0, 1 or 2", topic.to));
+            }
+        }
+        if let Payload::Behaviour(Behaviour::JsonMergeWith(template)) = &topic.payload {
+            if !template.is_object() {
+                return Err(format!("jsonMergeWith for topic \"{}\" must be a JSON object", topic.to));
+            }
+        }
+        if let Payload::Behaviour(Behaviour::CoerceSchema { schema }) = &topic.payload {
+            let schema = schema.as_object()
+                .ok_or_else(|| format!("coerceSchema schema for topic \"{}\" must be a JSON object", topic.to))?;
+            for (pointer, target_type) in schema {
+                if !pointer.starts_with('/') {
+                    return Err(format!("coerceSchema path \"{}\" for topic \"{}\" must be a JSON pointer starting with \"/\"", pointer, topic.to));
+                }
+                if !matches!(target_type.as_str(), Some("number") | Some("integer") | Some("boolean") | Some("string")) {
+                    return Err(format!("coerceSchema type for \"{}\" for topic \"{}\" must be one of \"number\", \"integer\", \"boolean\" or \"string\"", pointer, topic.to));
+                }
+            }
+        }
+    }
+
+    for (path, qos) in config.topics.iter().flat_map(Topic::iter_subscriptions) {
+        if rumqttc::qos(qos).is_err() {
+            return Err(format!("qos for topic \"{}\" must be 0, 1 or 2", path));
+        }
+    }
+
+    let mut seen_from = HashSet::new();
+    for from in config.topics.iter().flat_map(Topic::iter_from) {
+        if !seen_from.insert(from) {
+            return Err(format!("topic \"{}\" is listed in \"from\" more than once", from));
+        }
+        if config.disallow_wildcards && (from.contains('+') || from.contains('#')) {
+            return Err(format!("topic \"{}\" contains a wildcard, which disallowWildcards does not allow", from));
+        }
+    }
+
+    Ok(())
+}
+
+fn make_client(connection_cfg: &ConnectionConfig) -> (AsyncClient, EventLoop) {
+    let client_id = if connection_cfg.randomize_client_id {
+        let suffix = rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect::<String>();
+        format!("{}-{}", connection_cfg.client_id, suffix)
+    } else {
+        connection_cfg.client_id.clone()
+    };
+
+    if connection_cfg.effective_keep_alive() < connection_cfg.keep_alive {
+        println!("[WARNING] keepAlive of {}s for {} exceeds maxKeepAliveSecs, using {}s", connection_cfg.keep_alive.as_secs(), connection_cfg.host, connection_cfg.effective_keep_alive().as_secs());
+    }
+
+    if connection_cfg.tls_disable_sni {
+        println!("[SECURITY WARNING] TLS SNI is disabled for {}, only use this with brokers that reject SNI", connection_cfg.host);
+    }
+
+    let mqttoptions = make_mqtt_options(connection_cfg, client_id).unwrap_or_else(|e| panic!("{}", e));
+    AsyncClient::new(mqttoptions, connection_cfg.channel_capacity.unwrap_or(10))
+}
+
+fn make_mqtt_options(connection_cfg: &ConnectionConfig, client_id: String) -> Result<MqttOptions, String> {
+    let mut mqttoptions = MqttOptions::new(client_id, &connection_cfg.host, connection_cfg.port);
+    mqttoptions.set_keep_alive(connection_cfg.effective_keep_alive());
+    mqttoptions.set_inflight(connection_cfg.inflight);
+    mqttoptions.set_clean_session(connection_cfg.clean_session);
+    mqttoptions.set_connection_timeout(connection_cfg.conn_timeout.as_secs());
+
+    let credentials_file = match &connection_cfg.credentials_file {
+        Some(path) => Some(read_credentials_file(Path::new(path)).map_err(|e| format!("Failed to read credentials file {}: {}", path, e))?),
+        None => None,
+    };
+
+    if let Auth::AuthPassword { login, password } = &connection_cfg.auth {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs().map_err(|e| format!("Failed to load platform certs: {}", e))? {
+            roots
+                .add(&rustls::Certificate(cert.0))
+                .map_err(|e| format!("Failed to add platform cert: {}", e))?;
         }
         let mut client_config = ClientConfig::new();
         client_config.root_store = roots;
-        mqttoptions.set_credentials(login, password);
+        client_config.enable_sni = !connection_cfg.tls_disable_sni;
+        match &credentials_file {
+            Some((login, password)) => mqttoptions.set_credentials(login, password),
+            None => mqttoptions.set_credentials(login, password),
+        };
         mqttoptions.set_transport(Transport::tls_with_config(client_config.into()));
     } else if let Auth::AuthCertificate { ca, client_cert, client_key, key_type } = &connection_cfg.auth {
-        let ca_bytes = fs::read(ca).expect("Failed to read CA certificate file");
-        let client_cert_bytes = fs::read(client_cert).expect("Failed to read client certificate file");
-        let client_key_bytes = fs::read(client_key).expect("Failed to read client key file");
+        let ca_bytes = fs::read(ca).map_err(|e| format!("Failed to read CA certificate file: {}", e))?;
+        let client_cert_bytes = fs::read(client_cert).map_err(|e| format!("Failed to read client certificate file: {}", e))?;
+        let client_key_bytes = fs::read(client_key).map_err(|e| format!("Failed to read client key file: {}", e))?;
+
+        if connection_cfg.tls_disable_sni {
+            let mut client_config = ClientConfig::new();
+            client_config.enable_sni = false;
+            client_config.root_store
+                .add_pem_file(&mut BufReader::new(Cursor::new(ca_bytes)))
+                .map_err(|_| String::from("Failed to parse CA certificate file"))?;
+            let certs = rumqttc::certs(&mut BufReader::new(Cursor::new(client_cert_bytes)))
+                .map_err(|_| String::from("Failed to parse client certificate file"))?;
+            let keys = match key_type {
+                KeyType::RSA => rumqttc::rsa_private_keys(&mut BufReader::new(Cursor::new(client_key_bytes))),
+                KeyType::ECC => rumqttc::pkcs8_private_keys(&mut BufReader::new(Cursor::new(client_key_bytes))),
+            }.map_err(|_| String::from("Failed to parse client key file"))?;
+            let key = keys.into_iter().next().ok_or_else(|| String::from("No private key found in client key file"))?;
+            client_config.set_single_client_cert(certs, key).map_err(|e| format!("Invalid client certificate or key: {}", e))?;
+            mqttoptions.set_transport(Transport::tls_with_config(client_config.into()));
+        } else {
+            let key = match key_type {
+                KeyType::RSA => Key::RSA(client_key_bytes),
+                KeyType::ECC => Key::ECC(client_key_bytes),
+            };
+
+            mqttoptions.set_transport(
+                Transport::Tls(TlsConfiguration::Simple {
+                    ca: ca_bytes,
+                    alpn: None,
+                    client_auth: Some((client_cert_bytes, key)),
+                })
+            );
+        }
+    }
+
+    if let (Auth::AuthCertificate { .. }, Some((login, password))) = (&connection_cfg.auth, &credentials_file) {
+        mqttoptions.set_credentials(login, password);
+    }
+
+    Ok(mqttoptions)
+}
+
+// Re-reads the config file and rebuilds the connection options for one side, keeping the current client id
+// Only replaces the MQTT options of the event loop, state kept elsewhere (e.g. the request channel or the
+// credentials file watcher) stays as it was at startup
+fn reload_connection_options(config_file_path: &Path, prefix: &str, connection_cfg: fn(&Config) -> &ConnectionConfig, eventloop: &mut EventLoop) {
+    let reloaded = fs::read_to_string(config_file_path)
+        .map_err(|e| e.to_string())
+        .and_then(|config_string| serde_json::from_str::<Config>(&config_string).map_err(|e| e.to_string()))
+        .and_then(|config| validate_config(&config).map(|_| config))
+        .and_then(|config| make_mqtt_options(connection_cfg(&config), eventloop.options.client_id()));
+    match reloaded {
+        Ok(mqttoptions) => {
+            println!("[{}] Reloaded connection settings from {}", prefix, config_file_path.display());
+            eventloop.options = mqttoptions;
+        },
+        Err(e) => println!("[{} ERROR] Failed to reload config, keeping current connection settings: {}", prefix, e),
+    }
+}
+
+fn notify_reconnect(dest_client: &AsyncClient, topic: &str, side: &str, attempt: u32, dest_inflight: Option<Arc<Semaphore>>) {
+    let payload = serde_json::json!({
+        "event": "reconnect",
+        "side": side,
+        "attempt": attempt,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+    let dest_client = dest_client.clone();
+    let topic = topic.to_string();
+    task::spawn(async move {
+        acquire_dest_permit(dest_inflight.as_deref()).await;
+        if let Err(e) = dest_client.publish(topic, QoS::AtMostOnce, false, payload.to_string()).await {
+            println!("[WARNING] Failed to publish reconnect notification: {}", e);
+        }
+    });
+}
+
+type LastValueCache = Arc<Mutex<HashMap<String, CachedValue>>>;
+
+struct CachedValue {
+    qos: QoS,
+    retain: bool,
+    payload: Bytes,
+    received: Instant,
+    ttl: Option<Duration>,
+}
+
+impl CachedValue {
+    fn is_expired(&self) -> bool {
+        self.ttl.is_some_and(|ttl| self.received.elapsed() > ttl)
+    }
+}
+
+fn republish_last_values(client: &AsyncClient, last_values: &LastValueCache, dest_inflight: Option<Arc<Semaphore>>) {
+    let snapshot = last_values.lock().unwrap().iter()
+        .filter(|(_, cached)| !cached.is_expired())
+        .map(|(topic, cached)| (topic.clone(), cached.qos, cached.retain, cached.payload.clone()))
+        .collect::<Vec<_>>();
+    if snapshot.is_empty() {
+        return;
+    }
+    println!("[DEST] Re-publishing {} cached last value(s)", snapshot.len());
+    // Sent from a separate task, the event loop has to keep polling to drain the request channel
+    let client = client.clone();
+    task::spawn(async move {
+        for (topic, qos, retain, payload) in snapshot {
+            acquire_dest_permit(dest_inflight.as_deref()).await;
+            if let Err(e) = client.publish_bytes(topic, qos, retain, payload).await {
+                println!("[DEST ERROR] Failed to re-publish cached last value: {}", e);
+            }
+        }
+    });
+}
+
+type ReplayBuffers = Arc<Mutex<HashMap<String, VecDeque<(Bytes, QoS, bool)>>>>;
+
+fn replay_messages(client: &AsyncClient, replay_buffers: &ReplayBuffers, dest_inflight: Option<Arc<Semaphore>>) {
+    let snapshot = replay_buffers.lock().unwrap().iter()
+        .flat_map(|(topic, buffer)| buffer.iter().map(move |(payload, qos, retain)| (topic.clone(), *qos, *retain, payload.clone())))
+        .collect::<Vec<_>>();
+    if snapshot.is_empty() {
+        return;
+    }
+    println!("[DEST] Replaying {} buffered message(s)", snapshot.len());
+    let client = client.clone();
+    task::spawn(async move {
+        for (topic, qos, retain, payload) in snapshot {
+            acquire_dest_permit(dest_inflight.as_deref()).await;
+            if let Err(e) = client.publish_bytes(topic, qos, retain, payload).await {
+                println!("[DEST ERROR] Failed to replay buffered message: {}", e);
+            }
+        }
+    });
+}
+
+struct Forward {
+    to: String,
+    qos: QoS,
+    retain: bool,
+    payload: Bytes,
+    // Sent to an error topic instead of the configured destination topic
+    rerouted: bool,
+}
+
+fn forward_message(topic: &Topic, publish: &Publish, is_verbose: bool) -> Option<Forward> {
+    let mut publish = Cow::Borrowed(publish);
+    if let Some(max_age_secs) = topic.max_age_secs {
+        match check_message_age(topic, &publish.payload, max_age_secs) {
+            MessageAge::Fresh => {},
+            MessageAge::Expired => {
+                if is_verbose {
+                    println!("[SRC->DEST] Dropping message older than {}s on {}", max_age_secs, publish.topic);
+                }
+                return None;
+            },
+            MessageAge::Unknown => match topic.unknown_timestamp_action {
+                UnknownTimestampAction::Forward => {},
+                UnknownTimestampAction::Drop => {
+                    if is_verbose {
+                        println!("[SRC->DEST] Dropping message without valid timestamp on {}", publish.topic);
+                    }
+                    return None;
+                },
+                UnknownTimestampAction::UseCurrentTime => {
+                    if let Some(payload) = set_current_timestamp(&publish.payload, topic.timestamp_pointer()) {
+                        publish.to_mut().payload = payload;
+                    }
+                },
+            },
+        }
+    }
+
+    let mut to = match &topic.to_case {
+        Some(case) => case.apply(&resolve_to_topic(&topic.to)),
+        None => resolve_to_topic(&topic.to),
+    };
+    if let Some(qos_suffix) = &topic.qos_suffix {
+        to.push_str(qos_suffix.for_qos(publish.qos));
+    }
+    let mut retain = publish.retain && !matches!(topic.payload, Payload::Behaviour(Behaviour::Omit));
+    let mut rerouted = false;
+    let transformed = match make_payload(&topic.payload, &publish, &to) {
+        Ok(transformed) => transformed,
+        Err(e) => match topic.on_transform_error {
+            TransformErrorAction::Drop => {
+                println!("[WARNING] {} on {}, dropping", e, publish.topic);
+                return None;
+            },
+            TransformErrorAction::Forward => {
+                println!("[WARNING] {} on {}, forwarding unchanged", e, publish.topic);
+                publish.payload.clone()
+            },
+            TransformErrorAction::ForwardToErrorTopic => {
+                let error_topic = topic.error_topic.clone().expect("errorTopic is validated for forwardToErrorTopic");
+                println!("[WARNING] {} on {}, forwarding unchanged to {}", e, publish.topic, error_topic);
+                to = error_topic;
+                retain = false;
+                rerouted = true;
+                publish.payload.clone()
+            },
+            TransformErrorAction::Exit => {
+                println!("[FATAL] {} on {}, exiting", e, publish.topic);
+                process::exit(1);
+            },
+        },
+    };
+    let payload = match apply_null_policy(&topic.null_policy, transformed) {
+        Some(payload) => payload,
+        None => {
+            if is_verbose {
+                println!("[SRC->DEST] Dropping payload with null field on {}", publish.topic);
+            }
+            return None;
+        }
+    };
+
+    Some(Forward { to, qos: topic.qos_policy.effective_qos(publish.qos), retain, payload, rerouted })
+}
+
+enum MessageAge {
+    Fresh,
+    Expired,
+    Unknown,
+}
+
+fn check_message_age(topic: &Topic, payload: &Bytes, max_age_secs: u64) -> MessageAge {
+    let value = match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(value) => value,
+        Err(_) => return MessageAge::Unknown,
+    };
+    let timestamp_ms = match value.pointer(topic.timestamp_pointer()).and_then(parse_timestamp_ms) {
+        Some(timestamp_ms) => timestamp_ms,
+        None => return MessageAge::Unknown,
+    };
+
+    let now_ms = Utc::now().timestamp_millis();
+    if now_ms - timestamp_ms > (max_age_secs as i64).saturating_mul(1000) {
+        MessageAge::Expired
+    } else {
+        MessageAge::Fresh
+    }
+}
+
+fn parse_timestamp_ms(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        // Unix epoch timestamps above this are assumed to be in milliseconds rather than seconds
+        serde_json::Value::Number(n) => n.as_f64().map(|t| if t > 1e11 { t as i64 } else { (t * 1000.0) as i64 }),
+        serde_json::Value::String(s) => DateTime::parse_from_rfc3339(s).ok().map(|t| t.timestamp_millis()),
+        _ => None,
+    }
+}
+
+fn set_current_timestamp(payload: &Bytes, pointer: &str) -> Option<Bytes> {
+    let mut value = serde_json::from_slice::<serde_json::Value>(payload).ok()?;
+    let (parent_pointer, key) = pointer.rsplit_once('/')?;
+    let parent = value.pointer_mut(parent_pointer)?.as_object_mut()?;
+    parent.insert(key.to_string(), serde_json::Value::from(Utc::now().timestamp()));
+    Some(value.to_string().into())
+}
+
+// Size of an MQTT 3.1.1 PUBLISH packet: fixed header, topic, packet id (QoS > 0) and payload
+fn publish_packet_size(topic: &str, qos: QoS, payload_len: usize) -> usize {
+    let remaining_len = 2 + topic.len() + if qos == QoS::AtMostOnce { 0 } else { 2 } + payload_len;
+    let remaining_len_bytes = match remaining_len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + remaining_len_bytes + remaining_len
+}
+
+fn enforce_packet_size(mut forward: Forward, max_size: u32, overflow_action: &OverflowAction) -> Option<Forward> {
+    let max_size = max_size as usize;
+    let size = publish_packet_size(&forward.to, forward.qos, forward.payload.len());
+    if size <= max_size {
+        return Some(forward);
+    }
+
+    match overflow_action {
+        OverflowAction::Drop => {
+            println!("[WARNING] Dropping {} byte publish to {}, maxPublishPacketSize is {}", size, forward.to, max_size);
+            None
+        },
+        OverflowAction::Truncate => {
+            let headers_size = publish_packet_size(&forward.to, forward.qos, 0);
+            let mut payload_len = match max_size.checked_sub(headers_size) {
+                Some(payload_len) => payload_len,
+                None => {
+                    println!("[WARNING] Dropping publish to {}, topic alone exceeds maxPublishPacketSize of {}", forward.to, max_size);
+                    return None;
+                },
+            };
+            // The remaining length field of a larger payload can take up to 3 more bytes than for an empty one
+            while publish_packet_size(&forward.to, forward.qos, payload_len) > max_size {
+                payload_len -= 1;
+            }
+            println!("[WARNING] Truncating {} byte publish to {} to fit maxPublishPacketSize of {}", size, forward.to, max_size);
+            forward.payload.truncate(payload_len);
+            Some(forward)
+        },
+        OverflowAction::SendToErrorTopic(error_topic) => {
+            println!("[WARNING] {} byte publish to {} exceeds maxPublishPacketSize of {}, reporting to {}", size, forward.to, max_size, error_topic);
+            let notice = serde_json::json!({ "topic": forward.to, "size": size, "maxPublishPacketSize": max_size });
+            Some(Forward { to: error_topic.clone(), qos: forward.qos, retain: false, payload: notice.to_string().into(), rerouted: true })
+        },
+    }
+}
+
+fn resolve_to_topic(to: &str) -> String {
+    if !to.contains('{') {
+        return to.to_string();
+    }
+
+    let now = Utc::now();
+    to.replace("{year}", &format!("{:04}", now.year()))
+        .replace("{month}", &format!("{:02}", now.month()))
+        .replace("{day}", &format!("{:02}", now.day()))
+        .replace("{hour}", &format!("{:02}", now.hour()))
+}
+
+fn make_payload(payload: &Payload, publish: &Publish, to: &str) -> Result<Bytes, &'static str> {
+    match payload {
+        Payload::Behaviour(Behaviour::Copy) => Ok(publish.payload.clone()),
+        Payload::Behaviour(Behaviour::Omit) => Ok(Bytes::new()),
+        Payload::Behaviour(Behaviour::InvertBoolean) => {
+            match String::from_utf8_lossy(&publish.payload).to_lowercase().as_str() {
+                "false" | "0" => Ok(Bytes::from("true")),
+                "true" | "1" => Ok(Bytes::from("false")),
+                _ => Err("invertBoolean received unrecognized payload"),
+            }
+        },
+        Payload::Behaviour(Behaviour::JsonMergeWith(template)) => {
+            match serde_json::from_slice::<serde_json::Value>(&publish.payload) {
+                Ok(mut value) if value.is_object() => {
+                    json_merge_missing(&mut value, template);
+                    Ok(value.to_string().into())
+                },
+                _ => Err("jsonMergeWith received a payload that is not a JSON object"),
+            }
+        },
+        Payload::Behaviour(Behaviour::CoerceSchema { schema }) => {
+            match serde_json::from_slice::<serde_json::Value>(&publish.payload) {
+                Ok(mut value) => {
+                    for (pointer, target_type) in schema.as_object().into_iter().flatten() {
+                        if let Some(field) = value.pointer_mut(pointer) {
+                            let target_type = target_type.as_str().unwrap_or_default();
+                            match coerce_json_value(field, target_type) {
+                                Some(coerced) => *field = coerced,
+                                None => println!("[WARNING] coerceSchema could not coerce {} to {} on {}, leaving unchanged", pointer, target_type, publish.topic),
+                            }
+                        }
+                    }
+                    Ok(value.to_string().into())
+                },
+                Err(_) => Err("coerceSchema received a payload that is not JSON"),
+            }
+        },
+        Payload::Behaviour(Behaviour::WrapInEnvelope { envelope_fields }) => {
+            Ok(wrap_in_envelope(envelope_fields, publish, to).to_string().into())
+        },
+        Payload::String(payload_string) => Ok(payload_string.clone().into()),
+        Payload::Bytes(bytes) => Ok(bytes.to_owned().into()),
+    }
+}
 
-        let key = match key_type {
-            KeyType::RSA => Key::RSA(client_key_bytes),
-            KeyType::ECC => Key::ECC(client_key_bytes),
+fn wrap_in_envelope(envelope_fields: &[EnvelopeField], publish: &Publish, to: &str) -> serde_json::Value {
+    let payload = serde_json::from_slice::<serde_json::Value>(&publish.payload)
+        .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&publish.payload).into_owned()));
+
+    let mut envelope = serde_json::Map::new();
+    for field in envelope_fields {
+        let (key, value) = match field {
+            EnvelopeField::SourceTopic => ("sourceTopic", serde_json::Value::from(publish.topic.as_str())),
+            EnvelopeField::DestTopic => ("destTopic", serde_json::Value::from(to)),
+            EnvelopeField::Timestamp => ("timestamp", serde_json::Value::from(Utc::now().to_rfc3339())),
+            EnvelopeField::MessageId => ("messageId", serde_json::Value::from(Uuid::new_v4().to_string())),
+            EnvelopeField::RepeaterVersion => ("repeaterVersion", serde_json::Value::from(VERSION)),
+            EnvelopeField::HopCount => {
+                let previous_hops = payload.get("hopCount").and_then(serde_json::Value::as_u64).unwrap_or(0);
+                ("hopCount", serde_json::Value::from(previous_hops + 1))
+            },
         };
+        envelope.insert(key.to_string(), value);
+    }
+    envelope.insert(String::from("payload"), payload);
+    serde_json::Value::Object(envelope)
+}
 
-        mqttoptions.set_transport(
-            Transport::Tls(TlsConfiguration::Simple {
-                ca: ca_bytes,
-                alpn: None,
-                client_auth: Some((client_cert_bytes, key)),
-            })
-        );
+fn coerce_json_value(value: &serde_json::Value, target_type: &str) -> Option<serde_json::Value> {
+    use serde_json::Value;
+
+    match (target_type, value) {
+        ("number", Value::Number(_)) | ("boolean", Value::Bool(_)) | ("string", Value::String(_)) => Some(value.clone()),
+        ("number", Value::String(s)) => {
+            let s = s.trim();
+            s.parse::<i64>().map(Value::from).ok()
+                .or_else(|| s.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number))
+        },
+        ("number", Value::Bool(b)) | ("integer", Value::Bool(b)) => Some(Value::from(*b as i64)),
+        ("integer", Value::Number(n)) => n.as_i64()
+            .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64))
+            .map(Value::from),
+        ("integer", Value::String(s)) => s.trim().parse::<i64>().map(Value::from).ok(),
+        ("boolean", Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" | "1" => Some(Value::Bool(true)),
+            "false" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        ("boolean", Value::Number(n)) => match n.as_f64() {
+            Some(1.0) => Some(Value::Bool(true)),
+            Some(0.0) => Some(Value::Bool(false)),
+            _ => None,
+        },
+        ("string", Value::Number(n)) => Some(Value::String(n.to_string())),
+        ("string", Value::Bool(b)) => Some(Value::String(b.to_string())),
+        _ => None,
+    }
+}
 
+fn apply_null_policy(null_policy: &JsonNullPolicy, payload: Bytes) -> Option<Bytes> {
+    if let JsonNullPolicy::Forward = null_policy {
+        return Some(payload);
     }
 
-    return AsyncClient::new(mqttoptions, 10);
+    let mut value = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(serde_json::Value::Object(value)) => value,
+        _ => return Some(payload),
+    };
+    if !value.values().any(serde_json::Value::is_null) {
+        return Some(payload);
+    }
+
+    match null_policy {
+        JsonNullPolicy::Drop => None,
+        _ => {
+            value.retain(|_, field| !field.is_null());
+            Some(serde_json::Value::Object(value).to_string().into())
+        },
+    }
+}
+
+fn json_merge_missing(target: &mut serde_json::Value, template: &serde_json::Value) {
+    if let (Some(target), Some(template)) = (target.as_object_mut(), template.as_object()) {
+        for (key, template_value) in template {
+            match target.get_mut(key) {
+                Some(target_value) => json_merge_missing(target_value, template_value),
+                None => { target.insert(key.clone(), template_value.clone()); },
+            }
+        }
+    }
 }
 
 fn print_event(prefix: &str, event: &Event) {
     print!("[{}] Received = {:?};", prefix, event);
     
-    if let Event::Incoming(packet) = event {
-        if let Packet::Publish(publish) = packet {
-            let payload = str::from_utf8(&publish.payload).unwrap();
-            print!("{}", payload);
+    if let Event::Incoming(Packet::Publish(publish)) = event {
+        let payload = str::from_utf8(&publish.payload).unwrap();
+        print!("{}", payload);
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn conn_timeout_fails_connect_without_connack() {
+        // Accepts TCP connections but never answers CONNECT
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let connection_cfg: ConnectionConfig = serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": port,
+            "auth": { "login": "login", "password": "password" },
+            "connTimeout": 1,
+        })).unwrap();
+        let (_client, mut eventloop) = make_client(&connection_cfg);
+        eventloop.options.set_transport(Transport::Tcp);
+
+        let started = std::time::Instant::now();
+        let polled = tokio::time::timeout(Duration::from_secs(3), eventloop.poll()).await.expect("connTimeout was not applied");
+        assert!(polled.is_err());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn durations_must_be_whole_seconds() {
+        let config = |conn_timeout: &str| -> Config {
+            let connection = serde_json::json!({ "host": "localhost", "auth": { "login": "login", "password": "password" }, "connTimeout": conn_timeout });
+            serde_json::from_value(serde_json::json!({ "source": connection, "destination": connection, "topics": [] })).unwrap()
+        };
+        assert_eq!(config("1500ms").source.conn_timeout, Duration::from_millis(1500));
+        assert!(validate_config(&config("1500ms")).is_err());
+        assert!(validate_config(&config("2s")).is_ok());
+    }
+
+    fn topic(config: serde_json::Value) -> Topic {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn retained_publish(payload: &'static str) -> Publish {
+        let mut publish = Publish::new("in", QoS::AtLeastOnce, payload);
+        publish.retain = true;
+        publish
+    }
+
+    #[test]
+    fn invert_boolean_unrecognized_payload_follows_on_transform_error() {
+        let publish = retained_publish("maybe");
+        let dropping = topic(serde_json::json!({ "from": "in", "to": "out", "payload": { "behaviour": "invertBoolean" } }));
+        assert!(make_payload(&dropping.payload, &publish, "out").is_err());
+        assert!(forward_message(&dropping, &publish, false).is_none());
+
+        let topic = topic(serde_json::json!({ "from": "in", "to": "out", "payload": { "behaviour": "invertBoolean" }, "onTransformError": "forward" }));
+        let forward = forward_message(&topic, &publish, false).unwrap();
+        assert_eq!(forward.to, "out");
+        assert_eq!(forward.payload, Bytes::from("maybe"));
+    }
+
+    #[test]
+    fn omit_forwards_empty_payload_without_retain() {
+        let topic = topic(serde_json::json!({ "from": "in", "to": "out", "payload": { "behaviour": "omit" } }));
+        let forward = forward_message(&topic, &retained_publish("42"), false).unwrap();
+        assert!(forward.payload.is_empty());
+        assert!(!forward.retain);
+    }
+
+    fn backdate(instant: &mut Instant, by: Duration) {
+        *instant = instant.checked_sub(by).unwrap();
+    }
+
+    #[test]
+    fn dedup_drops_duplicate_inside_window() {
+        let window = Duration::from_secs(60);
+        let mut deduplicator = Deduplicator::new(Some(window));
+        let payload = Bytes::from("21.5");
+        assert!(!deduplicator.is_duplicate("sensors/temp", &payload, window));
+        assert!(deduplicator.is_duplicate("sensors/temp", &payload, window));
+        assert!(!deduplicator.is_duplicate("sensors/temp", &Bytes::from("22.0"), window));
+        assert!(!deduplicator.is_duplicate("sensors/other", &Bytes::from("22.0"), window));
+    }
+
+    #[test]
+    fn dedup_forwards_duplicate_after_window_and_resets_timestamp() {
+        let window = Duration::from_secs(60);
+        let mut deduplicator = Deduplicator::new(None);
+        let payload = Bytes::from("21.5");
+        assert!(!deduplicator.is_duplicate("sensors/temp", &payload, window));
+        backdate(&mut deduplicator.last_forwarded.get_mut("sensors/temp").unwrap().1, window * 2);
+
+        assert!(!deduplicator.is_duplicate("sensors/temp", &payload, window));
+        assert!(deduplicator.last_forwarded["sensors/temp"].1.elapsed() < window);
+        assert!(deduplicator.is_duplicate("sensors/temp", &payload, window));
+    }
+
+    #[test]
+    fn dedup_evicts_stale_entries() {
+        let window = Duration::from_secs(60);
+        let mut deduplicator = Deduplicator::new(Some(window));
+        assert!(!deduplicator.is_duplicate("sensors/old", &Bytes::from("1"), window));
+        assert!(!deduplicator.is_duplicate("sensors/new", &Bytes::from("1"), window));
+        backdate(&mut deduplicator.last_forwarded.get_mut("sensors/old").unwrap().1, window * 2);
+
+        // Sweeps run at most once per window
+        deduplicator.evict_stale(Instant::now());
+        assert_eq!(deduplicator.last_forwarded.len(), 2);
+
+        backdate(&mut deduplicator.last_sweep, window);
+        deduplicator.evict_stale(Instant::now());
+        assert!(!deduplicator.last_forwarded.contains_key("sensors/old"));
+        assert!(deduplicator.last_forwarded.contains_key("sensors/new"));
+    }
+
+    #[test]
+    fn pending_subacks_ignore_acks_from_earlier_attempt() {
+        let mut pending_subacks = PendingSubAcks { unsent: 2, awaiting: HashSet::new() };
+        pending_subacks.sent(1);
+        pending_subacks.sent(2);
+        assert!(!pending_subacks.acked(1));
+
+        // Retried before the second SubAck arrived
+        pending_subacks = PendingSubAcks { unsent: 2, awaiting: HashSet::new() };
+        pending_subacks.sent(3);
+        assert!(!pending_subacks.acked(2));
+        pending_subacks.sent(4);
+        assert!(!pending_subacks.acked(3));
+        assert_eq!(pending_subacks.len(), 1);
+        assert!(pending_subacks.acked(4));
+    }
+
+    #[test]
+    fn coerce_json_value_converts_between_types() {
+        use serde_json::json;
+
+        assert_eq!(coerce_json_value(&json!(" 42 "), "number"), Some(json!(42)));
+        assert_eq!(coerce_json_value(&json!("21.5"), "number"), Some(json!(21.5)));
+        assert_eq!(coerce_json_value(&json!(true), "number"), Some(json!(1)));
+        assert_eq!(coerce_json_value(&json!(3.0), "integer"), Some(json!(3)));
+        assert_eq!(coerce_json_value(&json!(3.5), "integer"), None);
+        assert_eq!(coerce_json_value(&json!("TRUE"), "boolean"), Some(json!(true)));
+        assert_eq!(coerce_json_value(&json!(0), "boolean"), Some(json!(false)));
+        assert_eq!(coerce_json_value(&json!(2), "boolean"), None);
+        assert_eq!(coerce_json_value(&json!(21.5), "string"), Some(json!("21.5")));
+        assert_eq!(coerce_json_value(&json!("on"), "number"), None);
+        assert_eq!(coerce_json_value(&json!(null), "string"), None);
+        assert_eq!(coerce_json_value(&json!(1), "date"), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_and_resets() {
+        let policy = ReconnectPolicy { initial_backoff_ms: 100, max_backoff_ms: 500, max_jitter_fraction: 0.0, ..ReconnectPolicy::default() };
+        let mut backoff = Backoff::new(policy, "client");
+        let delays = (0..5).map(|_| backoff.next_delay().as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_fraction() {
+        let policy = ReconnectPolicy { initial_backoff_ms: 1000, max_backoff_ms: 1000, max_jitter_fraction: 0.2, ..ReconnectPolicy::default() };
+        let mut backoff = Backoff::new(policy, "client");
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(1000) && delay < Duration::from_millis(1200));
         }
     }
-    println!("");
+
+    #[test]
+    fn backoff_never_goes_below_tcp_reconnect_delay() {
+        let policy = ReconnectPolicy { initial_backoff_ms: 100, max_backoff_ms: 1000, max_jitter_fraction: 0.0, tcp_reconnect_delay_ms: Some(300), ..ReconnectPolicy::default() };
+        let mut backoff = Backoff::new(policy, "client");
+        let delays = (0..4).map(|_| backoff.next_delay().as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![300, 300, 400, 800]);
+        assert_eq!(backoff.min_delay(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn outgoing_qos_reads_unacknowledged_publish() {
+        let (_client, mut eventloop) = AsyncClient::new(MqttOptions::new("outgoing-qos-test", "localhost", 1883), 10);
+        eventloop.state.handle_outgoing_packet(Request::Publish(Publish::new("out", QoS::AtLeastOnce, "1"))).unwrap();
+        eventloop.state.handle_outgoing_packet(Request::Publish(Publish::new("out", QoS::ExactlyOnce, "2"))).unwrap();
+        assert_eq!(outgoing_qos(&eventloop, 1), Some(QoS::AtLeastOnce));
+        assert_eq!(outgoing_qos(&eventloop, 2), Some(QoS::ExactlyOnce));
+        assert_eq!(outgoing_qos(&eventloop, 3), None);
+        // Looking the QoS up leaves the publishes to be acknowledged
+        assert_eq!(eventloop.state.inflight(), 2);
+    }
+
+    #[test]
+    fn parse_timestamp_ms_accepts_seconds_milliseconds_and_rfc3339() {
+        use serde_json::json;
+
+        assert_eq!(parse_timestamp_ms(&json!(1_600_000_000)), Some(1_600_000_000_000));
+        assert_eq!(parse_timestamp_ms(&json!(1_600_000_000.5)), Some(1_600_000_000_500));
+        assert_eq!(parse_timestamp_ms(&json!(1_600_000_000_123u64)), Some(1_600_000_000_123));
+        assert_eq!(parse_timestamp_ms(&json!("2020-09-13T12:26:40.250Z")), Some(1_600_000_000_250));
+        assert_eq!(parse_timestamp_ms(&json!("2020-09-13T14:26:40+02:00")), Some(1_600_000_000_000));
+        assert_eq!(parse_timestamp_ms(&json!("yesterday")), None);
+        assert_eq!(parse_timestamp_ms(&json!(null)), None);
+    }
+
+    #[test]
+    fn separate_words_splits_each_topic_level() {
+        assert_eq!(separate_words("homeAssistant/LivingRoom/temperature", '_'), "home_assistant/living_room/temperature");
+        assert_eq!(separate_words("sensor-1 main_floor/Room2Temp", '-'), "sensor-1-main-floor/room2-temp");
+        assert_eq!(separate_words("HVAC/ZoneA", '_'), "hvac/zone_a");
+        assert_eq!(CaseConversion::KebabCase.apply("homeAssistant/+/state"), "home-assistant/+/state");
+    }
+
+    fn forward(qos: QoS, payload_len: usize) -> Forward {
+        Forward { to: String::from("out"), qos, retain: true, payload: Bytes::from(vec![b'x'; payload_len]), rerouted: false }
+    }
+
+    #[test]
+    fn publish_packet_size_counts_headers() {
+        // Fixed header, 2 byte topic length, topic and payload, plus packet id above QoS 0
+        assert_eq!(publish_packet_size("out", QoS::AtMostOnce, 10), 1 + 1 + 2 + 3 + 10);
+        assert_eq!(publish_packet_size("out", QoS::AtLeastOnce, 10), 1 + 1 + 2 + 3 + 2 + 10);
+        // Remaining length above 127 takes a second byte
+        assert_eq!(publish_packet_size("out", QoS::AtMostOnce, 123), 1 + 2 + 2 + 3 + 123);
+    }
+
+    #[test]
+    fn enforce_packet_size_applies_overflow_action() {
+        assert!(enforce_packet_size(forward(QoS::AtLeastOnce, 50), 100, &OverflowAction::Drop).is_some());
+        assert!(enforce_packet_size(forward(QoS::AtLeastOnce, 200), 100, &OverflowAction::Drop).is_none());
+
+        let truncated = enforce_packet_size(forward(QoS::AtLeastOnce, 200), 100, &OverflowAction::Truncate).unwrap();
+        assert_eq!(publish_packet_size(&truncated.to, truncated.qos, truncated.payload.len()), 100);
+        // Fits exactly even where the remaining length needs a second byte
+        let truncated = enforce_packet_size(forward(QoS::AtLeastOnce, 200), 131, &OverflowAction::Truncate).unwrap();
+        assert_eq!(truncated.payload.len(), 121);
+        assert_eq!(publish_packet_size(&truncated.to, truncated.qos, truncated.payload.len()), 131);
+        assert!(enforce_packet_size(forward(QoS::AtLeastOnce, 200), 5, &OverflowAction::Truncate).is_none());
+
+        let reported = enforce_packet_size(forward(QoS::AtLeastOnce, 200), 100, &OverflowAction::SendToErrorTopic(String::from("errors"))).unwrap();
+        assert_eq!(reported.to, "errors");
+        assert!(!reported.retain);
+        assert!(reported.rerouted);
+        let notice: serde_json::Value = serde_json::from_slice(&reported.payload).unwrap();
+        assert_eq!(notice["topic"], "out");
+        assert_eq!(notice["size"], publish_packet_size("out", QoS::AtLeastOnce, 200));
+    }
+
+    #[test]
+    fn monitor_filters_append_each_qos_suffix() {
+        let filters = |config: serde_json::Value| monitor_filters(&topic(config));
+        assert_eq!(filters(serde_json::json!({ "from": "in", "to": "Out/{year}" })), vec!["Out/+"]);
+        assert_eq!(
+            filters(serde_json::json!({ "from": "in", "to": "out", "qosSuffix": { "qos1Suffix": "-reliable", "qos2Suffix": "/exact" } })),
+            vec!["out", "out-reliable", "out/exact"],
+        );
+        assert_eq!(
+            filters(serde_json::json!({ "from": "in", "to": "MyOut/{hour}", "toCase": "kebabCase", "qosSuffix": { "qos0Suffix": "-fast", "qos1Suffix": "/slow" } })),
+            vec!["my-out/+", "my-out/+/slow"],
+        );
+    }
 }